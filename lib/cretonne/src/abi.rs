@@ -0,0 +1,64 @@
+//! ABI value legalization.
+//!
+//! A function signature that has been legalized for a specific ISA may require argument or
+//! return values whose Cretonne IR type doesn't match the value actually produced or consumed in
+//! the function body -- an `i64` split across two 32-bit registers, an `f32` passed as bits in a
+//! general-purpose register, or a large struct passed by reference. This module describes those
+//! conversions so the boundary legalizer in `legalizer.rs` can insert the instructions needed to
+//! bridge the gap in both directions.
+
+use ir::{Type, ArgumentType};
+
+/// Method for converting a `Type` to match an ABI argument or return `ArgumentType`.
+///
+/// The conversions are expressed from the point of view of a value flowing *into* the ABI slot;
+/// `legalizer.rs` applies them in that direction when building call and return arguments, and
+/// inverts them when reconstructing a value from the legalized entry block or call results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueConversion {
+    /// Split an integer into low and high halves with `isplit`.
+    IntSplit,
+    /// Split a vector into low and high halves with `vsplit`.
+    VectorSplit,
+    /// Bit-cast a non-integer value to an integer type of the same size.
+    IntBits,
+    /// Sign-extend the value to the specified, wider integer type.
+    Sext(Type),
+    /// Zero-extend the value to the specified, wider integer type.
+    Uext(Type),
+    /// Pass the value by reference: store it to a stack slot and pass a pointer of the specified
+    /// type instead.
+    ///
+    /// This is used for aggregates that are too large for the ABI to pass directly. The
+    /// recursion this produces terminates as soon as the pointer type matches the ABI argument.
+    StructArgument(Type),
+}
+
+/// Determine how to convert a value of type `ty` so it matches the ABI argument `arg`.
+///
+/// This assumes `ty` doesn't already match `arg.value_type`; callers check for that directly.
+pub fn legalize_abi_value(ty: Type, arg: &ArgumentType) -> ValueConversion {
+    let abi_ty = arg.value_type;
+
+    if ty.is_int() && abi_ty.is_int() {
+        if ty.bits() > abi_ty.bits() {
+            return ValueConversion::IntSplit;
+        }
+        return if arg.extension.is_sext() {
+            ValueConversion::Sext(abi_ty)
+        } else {
+            ValueConversion::Uext(abi_ty)
+        };
+    }
+
+    if ty.is_vector() && abi_ty.is_vector() {
+        return ValueConversion::VectorSplit;
+    }
+
+    if !ty.is_int() && abi_ty.is_int() && ty.bits() == abi_ty.bits() {
+        return ValueConversion::IntBits;
+    }
+
+    // Anything else too large to fit directly is passed by reference.
+    ValueConversion::StructArgument(abi_ty)
+}
@@ -13,42 +13,119 @@
 //! The legalizer does not deal with register allocation constraints. These constraints are derived
 //! from the encoding recipes, and solved later by the register allocator.
 
+use std::collections::{HashMap, HashSet};
+
 use abi::{legalize_abi_value, ValueConversion};
 use ir::{Function, Cursor, DataFlowGraph, InstructionData, Opcode, Inst, InstBuilder, Ebb, Type,
-         Value, Signature, SigRef, ArgumentType};
+         Value, Signature, SigRef, ArgumentType, ArgumentPurpose, ArgumentLoc, ExtFuncData,
+         ExternalName, FuncRef, CallConv, StackSlot, StackSlotData, StackSlotKind, StackSlots,
+         MemFlags};
 use ir::condcodes::IntCC;
 use ir::instructions::CallInfo;
+use flowgraph::ControlFlowGraph;
 use isa::{TargetIsa, Legalize};
+use libcall::{self, LibCall};
+use split::{self, Splits};
+
+/// An instruction that keeps getting replaced by its own expansion more than this many times is
+/// assumed to be stuck in an unsound legalization cycle rather than converging on something legal.
+const LEGALIZE_FUEL: u32 = 1_000;
 
-/// Legalize `func` for `isa`.
+/// Legalize `func` for `isa`, updating `cfg` to reflect any EBBs that legalization retargets or
+/// creates along the way.
 ///
 /// - Transform any instructions that don't have a legal representation in `isa`.
 /// - Fill out `func.encodings`.
 ///
-pub fn legalize_function(func: &mut Function, isa: &TargetIsa) {
+pub fn legalize_function(func: &mut Function, cfg: &mut ControlFlowGraph, isa: &TargetIsa) {
     legalize_signatures(func, isa);
 
     // TODO: This is very simplified and incomplete.
     func.encodings.resize(func.dfg.num_insts());
+
+    // Cache the `FuncRef` created for each `LibCall` so that repeated uses of the same runtime
+    // routine in this function share a single external function reference and `SigRef`.
+    let mut libcalls: HashMap<LibCall, FuncRef> = HashMap::new();
+
+    let call_conv = func.signature.call_conv;
+
+    // If the signature grew a hidden `StructReturn` pointer argument, find the entry-block value
+    // that holds it so `handle_return_abi` can store the overflow return values through it.
+    let sret_ptr = func.layout.entry_block().and_then(|entry| {
+        func.signature
+            .special_param_index(ArgumentPurpose::StructReturn)
+            .map(|i| func.dfg.ebb_params(entry)[i])
+    });
+
+    // Calls whose legalized signature has more arguments than fit in registers need somewhere to
+    // store the overflow before the call. Every call in the function shares a single outgoing
+    // argument stack slot, sized to fit the largest such call; only one call's outgoing arguments
+    // are ever live at a time.
+    // A libcall discovered below can introduce a signature that needs more outgoing argument
+    // space than any call visible here did, so this is grown on demand as expansion proceeds.
+    let outgoing_args_size = compute_outgoing_args_size(func);
+    let mut outgoing_slot = if outgoing_args_size > 0 {
+        Some(func.stack_slots
+                 .push(StackSlotData::new(StackSlotKind::OutgoingArg, outgoing_args_size)))
+    } else {
+        None
+    };
+
+    // Values split across an ABI boundary are cached here so that splitting the same value (or
+    // the same EBB parameter) at more than one use site doesn't emit redundant instructions.
+    let mut splits = Splits::new();
+
+    // EBBs touched by legalization, whose successors/predecessors in `cfg` need recomputing once
+    // we're done walking the layout. An EBB lands here either because an instruction inside it was
+    // rewritten, or because it's a brand new EBB an expansion created.
+    let mut dirty_ebbs: Vec<Ebb> = Vec::new();
+
+    // EBBs that existed before this walk began. Anything else we encounter is a brand new EBB an
+    // expansion created, which `cfg` has never seen and so always needs recomputing.
+    let original_ebbs: HashSet<Ebb> = func.layout.ebbs().collect();
+
     let mut pos = Cursor::new(&mut func.layout);
-    while let Some(_ebb) = pos.next_ebb() {
+    while let Some(ebb) = pos.next_ebb() {
+        if !original_ebbs.contains(&ebb) {
+            dirty_ebbs.push(ebb);
+        }
+
         // Keep track of the cursor position before the instruction being processed, so we can
         // double back when replacing instructions.
         let mut prev_pos = pos.position();
 
+        // Bounds how many times the instruction at `prev_pos` may be replaced by its own
+        // expansion before we give up on ever reaching a legal encoding for it.
+        let mut fuel = LEGALIZE_FUEL;
+
         while let Some(inst) = pos.next_inst() {
             let opcode = func.dfg[inst].opcode();
 
             // Check for ABI boundaries that need to be converted to the legalized signature.
-            if opcode.is_call() && handle_call_abi(&mut func.dfg, &mut pos) {
+            if opcode.is_call() &&
+               handle_call_abi(&mut func.dfg,
+                                &mut pos,
+                                &mut func.stack_slots,
+                                &mut splits,
+                                outgoing_slot) {
                 // Go back and legalize the inserted argument conversion instructions.
+                dirty_ebbs.push(ebb);
                 pos.set_position(prev_pos);
+                consume_fuel(&mut fuel, opcode);
                 continue;
             }
 
-            if opcode.is_return() && handle_return_abi(&mut func.dfg, &mut pos, &func.signature) {
+            if opcode.is_return() &&
+               handle_return_abi(&mut func.dfg,
+                                  &mut pos,
+                                  &mut func.stack_slots,
+                                  &mut splits,
+                                  &func.signature,
+                                  sret_ptr) {
                 // Go back and legalize the inserted return value conversion instructions.
+                dirty_ebbs.push(ebb);
                 pos.set_position(prev_pos);
+                consume_fuel(&mut fuel, opcode);
                 continue;
             }
 
@@ -66,27 +143,143 @@ pub fn legalize_function(func: &mut Function, isa: &TargetIsa) {
                     //    typically means expressing `i8` and `i16` arithmetic in terms if `i32`
                     //    operations on RISC targets. (It may or may not be beneficial to promote
                     //    small vector types versus splitting them.)
-                    // 4. TODO: Convert to library calls. For example, floating point operations on
-                    //    an ISA with no IEEE 754 support.
+                    // 4. Legalize::LibCall: Convert to a library call. For example, floating point
+                    //    operations on an ISA with no IEEE 754 support.
                     let changed = match action {
                         Legalize::Expand => expand(&mut pos, &mut func.dfg),
                         Legalize::Narrow => narrow(&mut pos, &mut func.dfg),
+                        Legalize::LibCall => {
+                            expand_libcall(&mut pos,
+                                           &mut func.dfg,
+                                           &mut func.stack_slots,
+                                           isa,
+                                           inst,
+                                           call_conv,
+                                           &mut libcalls,
+                                           &mut outgoing_slot)
+                        }
                     };
                     // If the current instruction was replaced, we need to double back and revisit
                     // the expanded sequence. This is both to assign encodings and possible to
                     // expand further.
-                    // There's a risk of infinite looping here if the legalization patterns are
-                    // unsound. Should we attempt to detect that?
                     if changed {
+                        dirty_ebbs.push(ebb);
                         pos.set_position(prev_pos);
+                        consume_fuel(&mut fuel, opcode);
+                        continue;
                     }
                 }
             }
 
             // Remember this position in case we need to double back.
             prev_pos = pos.position();
+            fuel = LEGALIZE_FUEL;
         }
     }
+
+    // The cursor's borrow of `func.layout` ends here, so we can hand the whole function to `cfg`.
+    dirty_ebbs.sort();
+    dirty_ebbs.dedup();
+    for ebb in dirty_ebbs {
+        cfg.recompute_ebb(func, ebb);
+    }
+}
+
+/// Charge one unit of `fuel` for doubling back to re-legalize the instruction at the current
+/// cursor position, panicking with a diagnostic naming `opcode` if the budget runs out.
+///
+/// This turns an unsound legalization pattern that never reaches a fixpoint into a reportable
+/// error instead of an infinite loop.
+fn consume_fuel(fuel: &mut u32, opcode: Opcode) {
+    *fuel = fuel
+        .checked_sub(1)
+        .unwrap_or_else(|| {
+                            panic!("Legalization of {} did not converge after {} iterations; \
+                                     the expansion is likely unsound",
+                                   opcode,
+                                   LEGALIZE_FUEL)
+                        });
+}
+
+/// Replace `inst` with a call to the runtime library routine that implements it, if one is known
+/// for `inst`'s opcode and controlling type variable.
+///
+/// The `SigRef` and `FuncRef` for a given `LibCall` are created once per `Function` and cached in
+/// `libcalls`, so repeated instructions needing the same routine all share a single external
+/// function reference.
+///
+/// Returns `true` if `inst` was replaced.
+fn expand_libcall(pos: &mut Cursor,
+                   dfg: &mut DataFlowGraph,
+                   stack_slots: &mut StackSlots,
+                   isa: &TargetIsa,
+                   inst: Inst,
+                   call_conv: CallConv,
+                   libcalls: &mut HashMap<LibCall, FuncRef>,
+                   outgoing_slot: &mut Option<StackSlot>)
+                   -> bool {
+    let opcode = dfg[inst].opcode();
+    let call = match libcall::for_opcode(opcode, dfg.ctrl_typevar(inst)) {
+        Some(call) => call,
+        None => return false,
+    };
+
+    let func_ref = *libcalls.entry(call).or_insert_with(|| {
+        let mut sig = Signature::new(call_conv);
+        for &arg in dfg.inst_args(inst) {
+            sig.argument_types.push(ArgumentType::new(dfg.value_type(arg)));
+        }
+        for &result in dfg.inst_results(inst) {
+            sig.return_types.push(ArgumentType::new(dfg.value_type(result)));
+        }
+
+        // This signature is built straight from `inst`'s raw operand and result types, so unlike
+        // every other signature in `dfg.signatures` it hasn't been through `legalize_signatures`
+        // yet. Do that now so `handle_call_abi` has ABI locations to work with instead of seeing
+        // an already-"legal" signature that trivially matches the call and skipping it.
+        legalize_sret_signature(&mut sig, isa);
+        isa.legalize_signature(&mut sig);
+
+        let sig_ref = dfg.signatures.push(sig);
+        dfg.ext_funcs
+            .push(ExtFuncData {
+                      name: ExternalName::LibCall(call),
+                      signature: sig_ref,
+                  })
+    });
+
+    // The shared outgoing argument stack slot was sized before any libcall signature existed, so
+    // grow it now if this one needs more room than it currently provides.
+    let sig_ref = dfg.ext_funcs[func_ref].signature;
+    let needed_bytes = signature_outgoing_bytes(&dfg.signatures[sig_ref]);
+    if needed_bytes > 0 {
+        match *outgoing_slot {
+            Some(slot) => {
+                if needed_bytes > stack_slots[slot].size {
+                    stack_slots[slot].size = needed_bytes;
+                }
+            }
+            None => {
+                *outgoing_slot =
+                    Some(stack_slots.push(StackSlotData::new(StackSlotKind::OutgoingArg, needed_bytes)));
+            }
+        }
+    }
+
+    // Insert the call ahead of `inst`, then alias `inst`'s old results to the call's results so
+    // we don't have to track down every use of the replaced values.
+    let args = dfg.inst_args(inst).to_vec();
+    let old_results = dfg.inst_results(inst).to_vec();
+
+    let call_inst = dfg.ins(pos).call(func_ref, &args);
+    let new_results = dfg.inst_results(call_inst).to_vec();
+    for (&old, &new) in old_results.iter().zip(&new_results) {
+        dfg.change_to_alias(old, new);
+    }
+
+    pos.remove_inst();
+
+    true
 }
 
 // Include legalization patterns that were generated by `gen_legalizer.py` from the `XForms` in
@@ -95,14 +288,49 @@ pub fn legalize_function(func: &mut Function, isa: &TargetIsa) {
 // Concretely, this defines private functions `narrow()`, and `expand()`.
 include!(concat!(env!("OUT_DIR"), "/legalizer.rs"));
 
+/// Compute the number of bytes of outgoing argument space a single legalized `sig` needs, i.e. the
+/// highest `offset + size` among its `ArgumentLoc::Stack` argument locations. Returns 0 if `sig`
+/// passes no arguments on the stack.
+fn signature_outgoing_bytes(sig: &Signature) -> u32 {
+    let mut bytes = 0;
+    for arg in &sig.argument_types {
+        if let ArgumentLoc::Stack(offset) = arg.location {
+            let end = offset as u32 + arg.value_type.bytes();
+            if end > bytes {
+                bytes = end;
+            }
+        }
+    }
+    bytes
+}
+
+/// Compute the number of bytes of outgoing argument space the largest call in `func` needs.
+///
+/// This walks every signature in `func.dfg.signatures` (they have already been legalized by the
+/// time this runs), whether or not a call uses it, and finds the highest `offset + size` among
+/// their `ArgumentLoc::Stack` argument locations. Returns 0 if no signature passes any arguments
+/// on the stack.
+fn compute_outgoing_args_size(func: &Function) -> u32 {
+    let mut max_bytes = 0;
+    for sig in func.dfg.signatures.values() {
+        let bytes = signature_outgoing_bytes(sig);
+        if bytes > max_bytes {
+            max_bytes = bytes;
+        }
+    }
+    max_bytes
+}
+
 /// Legalize all the function signatures in `func`.
 ///
 /// This changes all signatures to be ABI-compliant with full `ArgumentLoc` annotations. It doesn't
 /// change the entry block arguments, calls, or return instructions, so this can leave the function
 /// in a state with type discrepancies.
 fn legalize_signatures(func: &mut Function, isa: &TargetIsa) {
+    legalize_sret_signature(&mut func.signature, isa);
     isa.legalize_signature(&mut func.signature);
     for sig in func.dfg.signatures.keys() {
+        legalize_sret_signature(&mut func.dfg.signatures[sig], isa);
         isa.legalize_signature(&mut func.dfg.signatures[sig]);
     }
 
@@ -111,6 +339,58 @@ fn legalize_signatures(func: &mut Function, isa: &TargetIsa) {
     }
 }
 
+/// Number of return value registers the calling convention is assumed to provide before a
+/// signature needs a hidden `StructReturn` pointer. This is conservative until the ISA can report
+/// its own return-register budget.
+const MAX_RETURN_REGISTERS: usize = 2;
+
+/// Compute how many return value registers `return_types` would require on `isa`, rounding each
+/// value up to a whole number of `isa.pointer_bits()`-sized slots.
+fn num_return_registers_required(return_types: &[ArgumentType], isa: &TargetIsa) -> usize {
+    let register_bits = isa.pointer_bits() as usize;
+    return_types
+        .iter()
+        .map(|ret| {
+            let bits = ret.value_type.bits() as usize;
+            (bits + register_bits - 1) / register_bits
+        })
+        .sum()
+}
+
+/// If `sig` returns more values than fit in the assumed return registers, rewrite it to take a
+/// hidden pointer argument with `ArgumentPurpose::StructReturn` and drop the return values that
+/// no longer fit so they can be written through that pointer instead.
+///
+/// This is the return-side mirror of the argument handling in `legalize_inst_arguments`: where an
+/// oversized argument list falls back to the stack, an oversized return list falls back to an
+/// sret pointer supplied by the caller.
+fn legalize_sret_signature(sig: &mut Signature, isa: &TargetIsa) {
+    if num_return_registers_required(&sig.return_types, isa) <= MAX_RETURN_REGISTERS {
+        return;
+    }
+
+    let register_bits = isa.pointer_bits() as usize;
+
+    // Keep as many of the leading return values in registers as will fit; the rest move behind
+    // the sret pointer.
+    let mut regs_used = 0;
+    let mut kept = 0;
+    for ret in &sig.return_types {
+        let bits = ret.value_type.bits() as usize;
+        let slots = (bits + register_bits - 1) / register_bits;
+        if regs_used + slots > MAX_RETURN_REGISTERS {
+            break;
+        }
+        regs_used += slots;
+        kept += 1;
+    }
+    sig.return_types.truncate(kept);
+
+    sig.argument_types
+        .insert(0,
+                ArgumentType::special(isa.pointer_type(), ArgumentPurpose::StructReturn));
+}
+
 /// Legalize the entry block arguments after `func`'s signature has been legalized.
 ///
 /// The legalized signature may contain more arguments than the original signature, and the
@@ -139,19 +419,22 @@ fn legalize_entry_arguments(func: &mut Function, entry: Ebb) {
         next_arg = func.dfg.next_ebb_arg(arg);
 
         let arg_type = func.dfg.value_type(arg);
-        if arg_type == abi_types[abi_arg].value_type {
-            // No value translation is necessary, this argument matches the ABI type.
-            // Just use the original EBB argument value. This is the most common case.
+        if arg_type == abi_types[abi_arg].value_type &&
+           !is_stack_loc(abi_types[abi_arg].location) {
+            // No value translation is necessary, this argument matches the ABI type and is
+            // passed in a register. Just use the original EBB argument value. This is the most
+            // common case.
             func.dfg.put_ebb_arg(entry, arg);
             abi_arg += 1;
         } else {
             // Compute the value we want for `arg` from the legalized ABI arguments.
             let converted = convert_from_abi(&mut func.dfg,
                                              &mut pos,
-                                             entry,
+                                             &mut func.stack_slots,
                                              &mut abi_arg,
                                              abi_types,
-                                             arg_type);
+                                             arg_type,
+                                             &mut |dfg, ty| dfg.append_ebb_arg(entry, ty));
             // The old `arg` is no longer an attached EBB argument, but there are probably still
             // uses of the value. Make it an alias to the converted value.
             func.dfg.change_to_alias(arg, converted);
@@ -159,19 +442,41 @@ fn legalize_entry_arguments(func: &mut Function, entry: Ebb) {
     }
 }
 
+/// Return `true` if `loc` places a value on the stack rather than in a register.
+fn is_stack_loc(loc: ArgumentLoc) -> bool {
+    match loc {
+        ArgumentLoc::Stack(_) => true,
+        _ => false,
+    }
+}
+
 /// Compute original value of type `ty` from the legalized ABI arguments beginning at `abi_arg`.
 ///
+/// `get_arg` supplies a fresh register-valued ABI argument of the given type when the recursion
+/// bottoms out on one; callers pass an EBB-parameter-appending closure at function entry and a
+/// call-result-appending closure at a call site.
+///
 /// Update `abi_arg` to reflect the ABI arguments consumed and return the computed value.
-fn convert_from_abi(dfg: &mut DataFlowGraph,
+fn convert_from_abi<GetArg>(dfg: &mut DataFlowGraph,
                     pos: &mut Cursor,
-                    entry: Ebb,
+                    stack_slots: &mut StackSlots,
                     abi_arg: &mut usize,
                     abi_types: &[ArgumentType],
-                    ty: Type)
-                    -> Value {
+                    ty: Type,
+                    get_arg: &mut GetArg)
+                    -> Value
+    where GetArg: FnMut(&mut DataFlowGraph, Type) -> Value
+{
     // Terminate the recursion when we get the desired type.
     if ty == abi_types[*abi_arg].value_type {
-        return dfg.append_ebb_arg(entry, ty);
+        return if let ArgumentLoc::Stack(_) = abi_types[*abi_arg].location {
+            // This argument was passed on the stack; load it from its incoming stack slot instead
+            // of treating it as a register-valued EBB parameter.
+            let slot = stack_slots.push(StackSlotData::new(StackSlotKind::IncomingArg, ty.bytes()));
+            dfg.ins(pos).stack_load(ty, slot, 0)
+        } else {
+            get_arg(dfg, ty)
+        };
     }
 
     // Reconstruct how `ty` was legalized into the argument at `abi_arg`.
@@ -182,39 +487,44 @@ fn convert_from_abi(dfg: &mut DataFlowGraph,
         // Construct a `ty` by concatenating two ABI integers.
         ValueConversion::IntSplit => {
             let abi_ty = ty.half_width().expect("Invalid type for conversion");
-            let lo = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
-            let hi = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
+            let lo = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
+            let hi = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
             dfg.ins(pos).iconcat_lohi(lo, hi)
         }
         // Construct a `ty` by concatenating two halves of a vector.
         ValueConversion::VectorSplit => {
             let abi_ty = ty.half_vector().expect("Invalid type for conversion");
-            let lo = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
-            let hi = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
+            let lo = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
+            let hi = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
             dfg.ins(pos).vconcat(lo, hi)
         }
         // Construct a `ty` by bit-casting from an integer type.
         ValueConversion::IntBits => {
             assert!(!ty.is_int());
             let abi_ty = Type::int(ty.bits()).expect("Invalid type for conversion");
-            let arg = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
+            let arg = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
             dfg.ins(pos).bitcast(ty, arg)
         }
         // ABI argument is a sign-extended version of the value we want.
         ValueConversion::Sext(abi_ty) => {
-            let arg = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
+            let arg = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
             // TODO: Currently, we don't take advantage of the ABI argument being sign-extended.
             // We could insert an `assert_sreduce` which would fold with a following `sextend` of
             // this value.
             dfg.ins(pos).ireduce(ty, arg)
         }
         ValueConversion::Uext(abi_ty) => {
-            let arg = convert_from_abi(dfg, pos, entry, abi_arg, abi_types, abi_ty);
+            let arg = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, abi_ty, get_arg);
             // TODO: Currently, we don't take advantage of the ABI argument being sign-extended.
             // We could insert an `assert_ureduce` which would fold with a following `uextend` of
             // this value.
             dfg.ins(pos).ireduce(ty, arg)
         }
+        // The ABI argument is a pointer to the value; load it back out.
+        ValueConversion::StructArgument(ptr_ty) => {
+            let ptr = convert_from_abi(dfg, pos, stack_slots, abi_arg, abi_types, ptr_ty, get_arg);
+            dfg.ins(pos).load(ty, MemFlags::new(), ptr, 0)
+        }
     }
 }
 
@@ -231,6 +541,8 @@ fn convert_from_abi(dfg: &mut DataFlowGraph,
 ///
 fn convert_to_abi<PutArg>(dfg: &mut DataFlowGraph,
                           pos: &mut Cursor,
+                          stack_slots: &mut StackSlots,
+                          splits: &mut Splits,
                           value: Value,
                           put_arg: &mut PutArg)
     where PutArg: FnMut(&mut DataFlowGraph, Value) -> Option<ArgumentType>
@@ -245,28 +557,35 @@ fn convert_to_abi<PutArg>(dfg: &mut DataFlowGraph,
     let ty = dfg.value_type(value);
     match legalize_abi_value(ty, &arg_type) {
         ValueConversion::IntSplit => {
-            let (lo, hi) = dfg.ins(pos).isplit_lohi(value);
-            convert_to_abi(dfg, pos, lo, put_arg);
-            convert_to_abi(dfg, pos, hi, put_arg);
+            let (lo, hi) = split::isplit(dfg, pos, splits, value);
+            convert_to_abi(dfg, pos, stack_slots, splits, lo, put_arg);
+            convert_to_abi(dfg, pos, stack_slots, splits, hi, put_arg);
         }
         ValueConversion::VectorSplit => {
-            let (lo, hi) = dfg.ins(pos).vsplit(value);
-            convert_to_abi(dfg, pos, lo, put_arg);
-            convert_to_abi(dfg, pos, hi, put_arg);
+            let (lo, hi) = split::vsplit(dfg, pos, splits, value);
+            convert_to_abi(dfg, pos, stack_slots, splits, lo, put_arg);
+            convert_to_abi(dfg, pos, stack_slots, splits, hi, put_arg);
         }
         ValueConversion::IntBits => {
             assert!(!ty.is_int());
             let abi_ty = Type::int(ty.bits()).expect("Invalid type for conversion");
             let arg = dfg.ins(pos).bitcast(abi_ty, value);
-            convert_to_abi(dfg, pos, arg, put_arg);
+            convert_to_abi(dfg, pos, stack_slots, splits, arg, put_arg);
         }
         ValueConversion::Sext(abi_ty) => {
             let arg = dfg.ins(pos).sextend(abi_ty, value);
-            convert_to_abi(dfg, pos, arg, put_arg);
+            convert_to_abi(dfg, pos, stack_slots, splits, arg, put_arg);
         }
         ValueConversion::Uext(abi_ty) => {
             let arg = dfg.ins(pos).uextend(abi_ty, value);
-            convert_to_abi(dfg, pos, arg, put_arg);
+            convert_to_abi(dfg, pos, stack_slots, splits, arg, put_arg);
+        }
+        // Too large to pass by value: store it to a stack slot and pass a pointer instead.
+        ValueConversion::StructArgument(ptr_ty) => {
+            let slot = stack_slots.push(StackSlotData::new(StackSlotKind::OutgoingArg, ty.bytes()));
+            dfg.ins(pos).stack_store(value, slot, 0);
+            let ptr = dfg.ins(pos).stack_addr(ptr_ty, slot, 0);
+            convert_to_abi(dfg, pos, stack_slots, splits, ptr, put_arg);
         }
     }
 }
@@ -317,13 +636,24 @@ fn check_call_signature(dfg: &DataFlowGraph, inst: Inst) -> Option<SigRef> {
 
 /// Insert ABI conversion code for the arguments to the call or return instruction at `pos`.
 ///
-/// - `abi_args` is the number of arguments that the ABI signature requires.
+/// - `register_args` is the number of ABI arguments that are passed in registers, and therefore
+///   become explicit operands of the instruction. Arguments assigned an `ArgumentLoc::Stack`
+///   location are instead written into `outgoing_slot`, which must be `Some` whenever any ABI
+///   argument the instruction could need is stack-assigned.
+/// - `num_abi_args` is the *total* number of ABI arguments in the legalized signature, register
+///   and stack assigned alike. It can exceed `register_args` once some arguments are stack-
+///   assigned, so it -- not `register_args` -- is what sizes the scratch room this function needs
+///   while shuffling the instruction's value list around.
 /// - `get_abi_type` is a closure that can provide the desired `ArgumentType` for a given ABI
-///   argument number in `0..abi_args`.
+///   argument number, in signature order (register and stack arguments interleaved).
 ///
 fn legalize_inst_arguments<ArgType>(dfg: &mut DataFlowGraph,
                                     pos: &mut Cursor,
-                                    abi_args: usize,
+                                    stack_slots: &mut StackSlots,
+                                    splits: &mut Splits,
+                                    outgoing_slot: Option<StackSlot>,
+                                    register_args: usize,
+                                    num_abi_args: usize,
                                     mut get_abi_type: ArgType)
     where ArgType: FnMut(&DataFlowGraph, usize) -> ArgumentType
 {
@@ -340,7 +670,11 @@ fn legalize_inst_arguments<ArgType>(dfg: &mut DataFlowGraph,
 
     // Grow the value list to the right size and shift all the existing arguments to the right.
     // This lets us write the new argument values into the list without overwriting the old
-    // arguments.
+    // arguments. The scratch room has to hold `num_abi_args` entries -- one per ABI argument,
+    // register- or stack-assigned -- even though only the `register_args` register-assigned ones
+    // end up staying in the value list; only register arguments occupy a slot in the value list,
+    // stack arguments are written directly into `outgoing_slot` below instead, and the leftover
+    // room is dropped by the `truncate()` at the end.
     //
     // Before:
     //
@@ -352,31 +686,44 @@ fn legalize_inst_arguments<ArgType>(dfg: &mut DataFlowGraph,
     //
     //    <-->                     fixed_values
     //               <-----------> have_args
-    //        <------------------> abi_args
+    //        <------------------> num_abi_args
     //   [FFFF-------OOOOOOOOOOOOO]
     //               ^
     //               old_arg_offset
     //
-    // After writing the new arguments:
+    // After writing the new arguments and truncating:
     //
     //    <-->                     fixed_values
-    //        <------------------> abi_args
+    //        <------------------> register_args
     //   [FFFFNNNNNNNNNNNNNNNNNNNN]
     //
-    vlist.grow_at(fixed_values, abi_args - have_args, &mut dfg.value_lists);
-    let old_arg_offset = fixed_values + abi_args - have_args;
+    vlist.grow_at(fixed_values, num_abi_args - have_args, &mut dfg.value_lists);
+    let old_arg_offset = fixed_values + num_abi_args - have_args;
 
     let mut abi_arg = 0;
+    let mut reg_arg = 0;
     for old_arg in 0..have_args {
         let old_value = vlist.get(old_arg_offset + old_arg, &dfg.value_lists).unwrap();
         convert_to_abi(dfg,
                        pos,
+                       stack_slots,
+                       splits,
                        old_value,
                        &mut |dfg, arg| {
             let abi_type = get_abi_type(dfg, abi_arg);
             if dfg.value_type(arg) == abi_type.value_type {
                 // This is the argument type we need.
-                vlist.as_mut_slice(&mut dfg.value_lists)[fixed_values + abi_arg] = arg;
+                match abi_type.location {
+                    ArgumentLoc::Stack(offset) => {
+                        let slot = outgoing_slot
+                            .expect("instruction needs an outgoing argument stack slot");
+                        dfg.ins(pos).stack_store(arg, slot, offset);
+                    }
+                    _ => {
+                        vlist.as_mut_slice(&mut dfg.value_lists)[fixed_values + reg_arg] = arg;
+                        reg_arg += 1;
+                    }
+                }
                 abi_arg += 1;
                 None
             } else {
@@ -386,6 +733,10 @@ fn legalize_inst_arguments<ArgType>(dfg: &mut DataFlowGraph,
         });
     }
 
+    // Drop the reserved room beyond the register arguments actually written: stack arguments
+    // never occupy a value list slot, so the list should only be as long as `register_args`.
+    vlist.truncate(fixed_values + register_args, &mut dfg.value_lists);
+
     // Put the modified value list back.
     dfg[inst].put_value_list(vlist);
 }
@@ -400,7 +751,12 @@ fn legalize_inst_arguments<ArgType>(dfg: &mut DataFlowGraph,
 /// original return values. The call's result values will be adapted to match the new signature.
 ///
 /// Returns `true` if any instructions were inserted.
-fn handle_call_abi(dfg: &mut DataFlowGraph, pos: &mut Cursor) -> bool {
+fn handle_call_abi(dfg: &mut DataFlowGraph,
+                    pos: &mut Cursor,
+                    stack_slots: &mut StackSlots,
+                    splits: &mut Splits,
+                    outgoing_slot: Option<StackSlot>)
+                    -> bool {
     let inst = pos.current_inst().expect("Cursor must point to a call instruction");
 
     // Start by checking if the argument types already match the signature.
@@ -409,27 +765,193 @@ fn handle_call_abi(dfg: &mut DataFlowGraph, pos: &mut Cursor) -> bool {
         Some(s) => s,
     };
 
-    // OK, we need to fix the call arguments to match the ABI signature.
-    let abi_args = dfg.signatures[sig_ref].argument_types.len();
+    // If the callee's signature grew a hidden sret pointer, materialize a stack slot for the
+    // overflow return values and pass its address as that argument.
+    let sret_slot = insert_call_sret_argument(dfg, pos, inst, sig_ref, stack_slots);
+
+    // OK, we need to fix the call arguments to match the ABI signature. Only the register
+    // arguments become explicit call operands; stack arguments are written into `outgoing_slot`.
+    let register_args = dfg.signatures[sig_ref]
+        .argument_types
+        .iter()
+        .filter(|arg| !is_stack_loc(arg.location))
+        .count();
+    let num_abi_args = dfg.signatures[sig_ref].argument_types.len();
     legalize_inst_arguments(dfg,
                             pos,
-                            abi_args,
+                            stack_slots,
+                            splits,
+                            outgoing_slot,
+                            register_args,
+                            num_abi_args,
                             |dfg, abi_arg| dfg.signatures[sig_ref].argument_types[abi_arg]);
 
-    // TODO: Convert return values.
+    // Convert the in-register return values back to the types the call site originally expected,
+    // the same way `legalize_entry_arguments` reconstructs a function's incoming arguments from
+    // its ABI-legalized signature.
+    let return_types = dfg.signatures[sig_ref].return_types.clone();
+    legalize_call_results(dfg, pos, stack_slots, inst, &return_types);
+
+    // Load the overflow return values back out of the sret slot and alias them to the call's
+    // original result values, the same way the in-register returns are already handled by
+    // `convert_from_abi` at other ABI boundaries.
+    if let Some(slot) = sret_slot {
+        load_call_sret_results(dfg, pos, inst, sig_ref, slot);
+    }
 
     // Yes, we changed stuff.
     true
 }
 
+/// Insert ABI conversion code after `inst` to convert its in-register ABI return values back into
+/// the values the call site originally expected, mirroring how `legalize_entry_arguments`
+/// reconstructs a function's incoming arguments from its ABI-legalized signature.
+///
+/// Only the first `return_types.len()` results are touched. Any trailing results are overflow
+/// return values destined for the hidden sret pointer; `load_call_sret_results` handles those
+/// separately, so they're reattached to `inst` unchanged.
+fn legalize_call_results(dfg: &mut DataFlowGraph,
+                          pos: &mut Cursor,
+                          stack_slots: &mut StackSlots,
+                          inst: Inst,
+                          return_types: &[ArgumentType]) {
+    let in_regs = return_types.len();
+
+    // The conversions must come after the call produces its raw ABI results.
+    pos.goto_inst(inst);
+    pos.next_inst();
+
+    let mut abi_arg = 0;
+    let mut next_result = dfg.take_inst_results(inst);
+    while abi_arg < in_regs {
+        let result = next_result.expect("call is missing a declared in-register return value");
+        next_result = dfg.next_result(result);
+
+        let result_type = dfg.value_type(result);
+        if result_type == return_types[abi_arg].value_type {
+            // No conversion needed; the call already produces the ABI value directly.
+            dfg.attach_result(inst, result);
+            abi_arg += 1;
+        } else {
+            // Compute the value the call site wants from the legalized ABI return values.
+            let converted = convert_from_abi(dfg,
+                                             pos,
+                                             stack_slots,
+                                             &mut abi_arg,
+                                             return_types,
+                                             result_type,
+                                             &mut |dfg, ty| dfg.append_result(inst, ty));
+            // The old `result` is no longer an attached call result, but there are probably still
+            // uses of the value. Make it an alias to the converted value.
+            dfg.change_to_alias(result, converted);
+        }
+    }
+
+    // Reattach any trailing overflow results untouched.
+    while let Some(result) = next_result {
+        next_result = dfg.next_result(result);
+        dfg.attach_result(inst, result);
+    }
+}
+
+/// If `sig_ref`'s signature has a `StructReturn` argument, allocate a stack slot sized to hold
+/// all of the call's original return values (which covers the overflow ones written through the
+/// sret pointer), insert the slot's address as that argument of `inst`, and return the slot so
+/// the caller can load the results back out of it.
+fn insert_call_sret_argument(dfg: &mut DataFlowGraph,
+                              pos: &mut Cursor,
+                              inst: Inst,
+                              sig_ref: SigRef,
+                              stack_slots: &mut StackSlots)
+                              -> Option<StackSlot> {
+    let sret_arg = dfg.signatures[sig_ref]
+        .special_param_index(ArgumentPurpose::StructReturn)?;
+    let ptr_type = dfg.signatures[sig_ref].argument_types[sret_arg].value_type;
+
+    let bytes: u32 = dfg.inst_results(inst)
+        .iter()
+        .map(|&v| dfg.value_type(v).bytes())
+        .sum();
+    let slot = stack_slots.push(StackSlotData::new(StackSlotKind::OutgoingArg, bytes));
+    let addr = dfg.ins(pos).stack_addr(ptr_type, slot, 0);
+
+    let mut vlist = dfg[inst].take_value_list().expect("Call must have a value list");
+    let fixed_values = dfg[inst].opcode().constraints().fixed_value_arguments();
+    vlist.insert(fixed_values + sret_arg, addr, &mut dfg.value_lists);
+    dfg[inst].put_value_list(vlist);
+
+    Some(slot)
+}
+
+/// Load the return values that overflowed into `slot` and alias them to `inst`'s original result
+/// values, which are no longer produced directly by the call.
+fn load_call_sret_results(dfg: &mut DataFlowGraph,
+                           pos: &mut Cursor,
+                           inst: Inst,
+                           sig_ref: SigRef,
+                           slot: StackSlot) {
+    let in_regs = dfg.signatures[sig_ref].return_types.len();
+    let results = dfg.inst_results(inst).to_vec();
+
+    // Detach the overflow results from the call, keeping only the in-register ones attached.
+    dfg.take_inst_results(inst);
+    for &result in &results[..in_regs] {
+        dfg.attach_result(inst, result);
+    }
+
+    // Move past the call; the loads must come after it.
+    pos.goto_inst(inst);
+    pos.next_inst();
+
+    let mut offset = 0;
+    for &result in &results[in_regs..] {
+        let ty = dfg.value_type(result);
+        let loaded = dfg.ins(pos).stack_load(ty, slot, offset);
+        dfg.change_to_alias(result, loaded);
+        offset += ty.bytes() as i32;
+    }
+}
+
 /// Insert ABI conversion code before and after the call instruction at `pos`.
 ///
 /// Return `true` if any instructions were inserted.
-fn handle_return_abi(dfg: &mut DataFlowGraph, pos: &mut Cursor, sig: &Signature) -> bool {
+fn handle_return_abi(dfg: &mut DataFlowGraph,
+                      pos: &mut Cursor,
+                      stack_slots: &mut StackSlots,
+                      splits: &mut Splits,
+                      sig: &Signature,
+                      sret_ptr: Option<Value>)
+                      -> bool {
     let inst = pos.current_inst().expect("Cursor must point to a return instruction");
-
-    // Check if the returned types already match the signature.
     let fixed_values = dfg[inst].opcode().constraints().fixed_value_arguments();
+
+    // Write any return values that no longer fit in the legalized signature's return list through
+    // the hidden sret pointer, then drop them from the return instruction's arguments.
+    let mut changed = false;
+    if let Some(ptr) = sret_ptr {
+        let ret_values: Vec<Value> = dfg[inst]
+            .arguments(&dfg.value_lists)
+            .iter()
+            .skip(fixed_values)
+            .cloned()
+            .collect();
+        let in_regs = sig.return_types.len();
+        if ret_values.len() > in_regs {
+            let mut offset = 0;
+            for &value in &ret_values[in_regs..] {
+                let ty = dfg.value_type(value);
+                dfg.ins(pos).store(MemFlags::new(), value, ptr, offset);
+                offset += ty.bytes() as i32;
+            }
+
+            let mut vlist = dfg[inst].take_value_list().expect("Return must have a value list");
+            vlist.truncate(fixed_values + in_regs, &mut dfg.value_lists);
+            dfg[inst].put_value_list(vlist);
+            changed = true;
+        }
+    }
+
+    // Check if the (possibly just-truncated) returned types already match the signature.
     if check_arg_types(dfg,
                        dfg[inst]
                            .arguments(&dfg.value_lists)
@@ -437,12 +959,63 @@ fn handle_return_abi(dfg: &mut DataFlowGraph, pos: &mut Cursor, sig: &Signature)
                            .skip(fixed_values)
                            .cloned(),
                        &sig.return_types[..]) {
-        return false;
+        return changed;
     }
 
-    let abi_args = sig.return_types.len();
-    legalize_inst_arguments(dfg, pos, abi_args, |_, abi_arg| sig.return_types[abi_arg]);
+    // Returns are never stack-assigned by this legalizer, so every return value is a register
+    // argument and there's no outgoing stack slot to fill.
+    let register_args = sig.return_types.len();
+    legalize_inst_arguments(dfg,
+                            pos,
+                            stack_slots,
+                            splits,
+                            None,
+                            register_args,
+                            register_args,
+                            |_, abi_arg| sig.return_types[abi_arg]);
 
     // Yes, we changed stuff.
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack_arg(ty: Type, offset: i32) -> ArgumentType {
+        let mut arg = ArgumentType::new(ty);
+        arg.location = ArgumentLoc::Stack(offset);
+        arg
+    }
+
+    #[test]
+    fn outgoing_bytes_ignores_register_args() {
+        let mut sig = Signature::new(CallConv::Fast);
+        sig.argument_types.push(ArgumentType::new(Type::int(32).unwrap()));
+        assert_eq!(signature_outgoing_bytes(&sig), 0);
+    }
+
+    #[test]
+    fn outgoing_bytes_is_the_highest_stack_extent() {
+        let mut sig = Signature::new(CallConv::Fast);
+        sig.argument_types.push(stack_arg(Type::int(32).unwrap(), 0));
+        sig.argument_types.push(stack_arg(Type::int(64).unwrap(), 4));
+        // The i64 at offset 4 ends at byte 12, past the i32 at offset 0.
+        assert_eq!(signature_outgoing_bytes(&sig), 12);
+    }
+
+    #[test]
+    fn compute_outgoing_args_size_picks_the_largest_signature() {
+        let mut func = Function::new();
+
+        let mut small = Signature::new(CallConv::Fast);
+        small.argument_types.push(stack_arg(Type::int(32).unwrap(), 0));
+        func.dfg.signatures.push(small);
+
+        let mut large = Signature::new(CallConv::Fast);
+        large.argument_types.push(stack_arg(Type::int(64).unwrap(), 0));
+        func.dfg.signatures.push(large);
+
+        assert_eq!(compute_outgoing_args_size(&func), 8);
+    }
+}
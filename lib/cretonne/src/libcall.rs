@@ -0,0 +1,81 @@
+//! Naming well-known routines in the runtime library.
+
+use std::fmt;
+use ir::{Opcode, Type};
+use ir::types::{F32, F64, I64};
+
+/// The name of a runtime library routine.
+///
+/// Runtime library calls are generated for Cretonne IR instructions that don't have an encoding
+/// recipe in the target ISA and can't be expanded or narrowed into legal instructions either. A
+/// `LibCall` identifies the out-of-line routine that implements the instruction's semantics so
+/// the legalizer can replace the instruction with a call to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LibCall {
+    /// ceil.f32
+    CeilF32,
+    /// ceil.f64
+    CeilF64,
+    /// floor.f32
+    FloorF32,
+    /// floor.f64
+    FloorF64,
+    /// trunc.f32
+    TruncF32,
+    /// trunc.f64
+    TruncF64,
+    /// nearest.f32
+    NearestF32,
+    /// nearest.f64
+    NearestF64,
+    /// Signed 64-bit division.
+    SdivI64,
+    /// Unsigned 64-bit division.
+    UdivI64,
+    /// Signed 64-bit remainder.
+    SremI64,
+    /// Unsigned 64-bit remainder.
+    UremI64,
+}
+
+impl fmt::Display for LibCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        LibCall::CeilF32 => "ceilf",
+                        LibCall::CeilF64 => "ceil",
+                        LibCall::FloorF32 => "floorf",
+                        LibCall::FloorF64 => "floor",
+                        LibCall::TruncF32 => "truncf",
+                        LibCall::TruncF64 => "trunc",
+                        LibCall::NearestF32 => "nearbyintf",
+                        LibCall::NearestF64 => "nearbyint",
+                        LibCall::SdivI64 => "__divdi3",
+                        LibCall::UdivI64 => "__udivdi3",
+                        LibCall::SremI64 => "__moddi3",
+                        LibCall::UremI64 => "__umoddi3",
+                    })
+    }
+}
+
+/// Find the runtime library routine that implements `opcode` for a controlling type variable of
+/// `ctrl_type`, if one is known.
+///
+/// Returns `None` if there's no library routine for this combination, in which case the
+/// legalizer should fall back to some other strategy (or fail to legalize the instruction).
+pub fn for_opcode(opcode: Opcode, ctrl_type: Type) -> Option<LibCall> {
+    Some(match (opcode, ctrl_type) {
+             (Opcode::Ceil, F32) => LibCall::CeilF32,
+             (Opcode::Ceil, F64) => LibCall::CeilF64,
+             (Opcode::Floor, F32) => LibCall::FloorF32,
+             (Opcode::Floor, F64) => LibCall::FloorF64,
+             (Opcode::Trunc, F32) => LibCall::TruncF32,
+             (Opcode::Trunc, F64) => LibCall::TruncF64,
+             (Opcode::Nearest, F32) => LibCall::NearestF32,
+             (Opcode::Nearest, F64) => LibCall::NearestF64,
+             (Opcode::Sdiv, I64) => LibCall::SdivI64,
+             (Opcode::Udiv, I64) => LibCall::UdivI64,
+             (Opcode::Srem, I64) => LibCall::SremI64,
+             (Opcode::Urem, I64) => LibCall::UremI64,
+             _ => return None,
+         })
+}
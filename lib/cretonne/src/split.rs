@@ -0,0 +1,231 @@
+//! Value splitting.
+//!
+//! Wide values -- an `i64` on a 32-bit ISA, or an oversized vector type -- are split into low and
+//! high halves when they cross an ABI boundary that can't carry the whole value at once. A value
+//! can reach more than one such boundary, and it can also be an EBB parameter rather than a
+//! straight-line definition, so the splitting logic lives here instead of inline at each call
+//! site in `legalizer.rs`:
+//!
+//! - A value is split at most once. Repeated requests to split the same value reuse the cached
+//!   `(lo, hi)` pair instead of emitting redundant `isplit`/`vsplit` instructions.
+//! - Splitting an EBB parameter replaces it with two half-width parameters, then walks every
+//!   branch or jump instruction targeting that EBB and splits the corresponding outgoing
+//!   argument, recursing as needed.
+//! - The cache doubles as a visited set, so a value that flows around a loop back-edge converges
+//!   on the first pair of halves computed for it instead of being split again on every iteration.
+
+use std::collections::HashMap;
+use ir::{Cursor, DataFlowGraph, Ebb, Inst, InstBuilder, Type, Value};
+
+/// Per-function cache of values that have already been split.
+///
+/// `isplit`/`vsplit` use separate caches because a vector and an integer split never share a
+/// value, but keeping them apart also keeps each cache's `(lo, hi)` pairs unambiguous.
+#[derive(Default)]
+pub struct Splits {
+    int: HashMap<Value, (Value, Value)>,
+    vector: HashMap<Value, (Value, Value)>,
+}
+
+impl Splits {
+    /// Create an empty split cache for a new function.
+    pub fn new() -> Splits {
+        Splits::default()
+    }
+}
+
+/// Split `value` into low and high halves with `isplit`, across EBB boundaries if necessary.
+///
+/// Returns the cached halves if `value` has already been split.
+pub fn isplit(dfg: &mut DataFlowGraph, pos: &mut Cursor, splits: &mut Splits, value: Value) -> (Value, Value) {
+    split_value(dfg, pos, splits, value, Kind::Int)
+}
+
+/// Split `value` into low and high vector halves with `vsplit`, across EBB boundaries if
+/// necessary.
+///
+/// Returns the cached halves if `value` has already been split.
+pub fn vsplit(dfg: &mut DataFlowGraph, pos: &mut Cursor, splits: &mut Splits, value: Value) -> (Value, Value) {
+    split_value(dfg, pos, splits, value, Kind::Vector)
+}
+
+/// Fuse previously-split halves of `value` back into a whole value with `iconcat`, if `value` was
+/// split with `isplit`. Panics if `value` was never split; callers should only concatenate values
+/// they know were split.
+pub fn iconcat(dfg: &mut DataFlowGraph, pos: &mut Cursor, splits: &Splits, value: Value) -> Value {
+    let &(lo, hi) = splits
+        .int
+        .get(&value)
+        .expect("value was never split with isplit");
+    dfg.ins(pos).iconcat_lohi(lo, hi)
+}
+
+/// Fuse previously-split halves of `value` back into a whole value with `vconcat`, if `value` was
+/// split with `vsplit`. Panics if `value` was never split.
+pub fn vconcat(dfg: &mut DataFlowGraph, pos: &mut Cursor, splits: &Splits, value: Value) -> Value {
+    let &(lo, hi) = splits
+        .vector
+        .get(&value)
+        .expect("value was never split with vsplit");
+    dfg.ins(pos).vconcat(lo, hi)
+}
+
+enum Kind {
+    Int,
+    Vector,
+}
+
+impl Kind {
+    fn half_type(&self, ty: Type) -> Type {
+        match *self {
+            Kind::Int => ty.half_width().expect("type can't be split further"),
+            Kind::Vector => ty.half_vector().expect("type can't be split further"),
+        }
+    }
+
+    fn emit(&self, dfg: &mut DataFlowGraph, pos: &mut Cursor, value: Value) -> (Value, Value) {
+        match *self {
+            Kind::Int => dfg.ins(pos).isplit_lohi(value),
+            Kind::Vector => dfg.ins(pos).vsplit(value),
+        }
+    }
+
+    /// Fuse `value`'s already-cached halves back into a whole value.
+    fn concat(&self, dfg: &mut DataFlowGraph, pos: &mut Cursor, splits: &Splits, value: Value) -> Value {
+        match *self {
+            Kind::Int => iconcat(dfg, pos, splits, value),
+            Kind::Vector => vconcat(dfg, pos, splits, value),
+        }
+    }
+
+    fn cache<'a>(&self, splits: &'a Splits) -> &'a HashMap<Value, (Value, Value)> {
+        match *self {
+            Kind::Int => &splits.int,
+            Kind::Vector => &splits.vector,
+        }
+    }
+
+    fn cache_mut<'a>(&self, splits: &'a mut Splits) -> &'a mut HashMap<Value, (Value, Value)> {
+        match *self {
+            Kind::Int => &mut splits.int,
+            Kind::Vector => &mut splits.vector,
+        }
+    }
+}
+
+/// Split `value`, recursing into predecessor EBBs when it's an EBB parameter rather than a
+/// straight-line definition.
+///
+/// Consults the cache first and populates it afterwards, so a value reached more than once --
+/// whether directly or by walking predecessor branches -- is only ever split once.
+fn split_value(dfg: &mut DataFlowGraph,
+               pos: &mut Cursor,
+               splits: &mut Splits,
+               value: Value,
+               kind: Kind)
+               -> (Value, Value) {
+    if let Some(&halves) = kind.cache(splits).get(&value) {
+        return halves;
+    }
+    let halves = match dfg.ebb_param_owner(value) {
+        Some(ebb) => split_ebb_param(dfg, pos, splits, ebb, value, &kind),
+        None => kind.emit(dfg, pos, value),
+    };
+    kind.cache_mut(splits).insert(value, halves);
+    halves
+}
+
+/// Replace the EBB parameter `value` of `ebb` with two half-width parameters, mark it as split
+/// before recursing (so a loop back-edge finds the cache instead of looping forever), then push
+/// the split through every branch or jump instruction that targets `ebb`.
+fn split_ebb_param(dfg: &mut DataFlowGraph,
+                    pos: &mut Cursor,
+                    splits: &mut Splits,
+                    ebb: Ebb,
+                    value: Value,
+                    kind: &Kind)
+                    -> (Value, Value) {
+    let ty = dfg.value_type(value);
+    let half_ty = kind.half_type(ty);
+
+    // Find `value`'s position among `ebb`'s current parameters; every branch targeting `ebb`
+    // supplies its outgoing arguments in the same order.
+    let param_index = dfg.ebb_params(ebb)
+        .iter()
+        .position(|&p| p == value)
+        .expect("value must be a parameter of ebb");
+
+    let lo = dfg.replace_ebb_param(value, half_ty);
+    let hi = dfg.insert_ebb_param(ebb, half_ty, param_index + 1);
+
+    // Record the split before walking predecessors, turning a cyclic flow around a loop back-edge
+    // into a fixpoint instead of unbounded recursion.
+    kind.cache_mut(splits).insert(value, (lo, hi));
+
+    // `value` is no longer an attached EBB parameter, but it may still be used directly by an
+    // instruction in `ebb`'s own body rather than only passed along through a branch. Splice a
+    // concat of the new halves in right after the parameters and alias the old value to it, so
+    // those uses keep seeing the whole value; dead code elimination cleans this up if nothing in
+    // the body actually used `value` directly.
+    let mut top_pos = Cursor::new(pos.layout_mut());
+    top_pos.goto_top(ebb);
+    top_pos.next_inst();
+    let whole = kind.concat(dfg, &mut top_pos, splits, value);
+    dfg.change_to_alias(value, whole);
+
+    // Patching predecessor branches walks the whole function and repositions a cursor as it goes,
+    // possibly into a different EBB entirely. Do that with a cursor of its own instead of `pos`,
+    // which the caller -- still in the middle of converting the call or return that triggered this
+    // split -- needs to find untouched when we return.
+    let mut branch_pos = Cursor::new(pos.layout_mut());
+    for inst in branches_to(dfg, &mut branch_pos, ebb) {
+        split_branch_argument(dfg, &mut branch_pos, splits, inst, param_index, kind);
+    }
+
+    (lo, hi)
+}
+
+/// Find every branch or jump instruction in the function that targets `ebb`.
+fn branches_to(dfg: &DataFlowGraph, pos: &mut Cursor, ebb: Ebb) -> Vec<Inst> {
+    let mut found = Vec::new();
+    let mut scan = Cursor::new(pos.layout_mut());
+    while let Some(_ebb) = scan.next_ebb() {
+        while let Some(inst) = scan.next_inst() {
+            if dfg[inst].branch_destination() == Some(ebb) {
+                found.push(inst);
+            }
+        }
+    }
+    found
+}
+
+/// Split the outgoing argument of `inst` at `param_index` into two, growing `inst`'s argument
+/// list to match the EBB parameter it was just split into.
+fn split_branch_argument(dfg: &mut DataFlowGraph,
+                          pos: &mut Cursor,
+                          splits: &mut Splits,
+                          inst: Inst,
+                          param_index: usize,
+                          kind: &Kind) {
+    let fixed_values = dfg[inst].opcode().constraints().fixed_value_arguments();
+    let mut vlist = dfg[inst].take_value_list().expect("branch must have a value list");
+    let arg = vlist.get(fixed_values + param_index, &dfg.value_lists).unwrap();
+
+    pos.goto_inst(inst);
+    let (lo, hi) = split_value(dfg, pos, splits, arg, clone_kind(kind));
+
+    vlist.grow_at(fixed_values + param_index, 1, &mut dfg.value_lists);
+    {
+        let slice = vlist.as_mut_slice(&mut dfg.value_lists);
+        slice[fixed_values + param_index] = lo;
+        slice[fixed_values + param_index + 1] = hi;
+    }
+    dfg[inst].put_value_list(vlist);
+}
+
+fn clone_kind(kind: &Kind) -> Kind {
+    match *kind {
+        Kind::Int => Kind::Int,
+        Kind::Vector => Kind::Vector,
+    }
+}